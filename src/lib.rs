@@ -7,6 +7,8 @@ extern crate crypto;
 use std::vec::Vec;
 use std::boxed::Box;
 use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fmt;
 use std::fmt::Display;
 use std::fmt::Debug;
@@ -23,22 +25,47 @@ pub fn hash_key<'b, S: Into<&'b str>>(key: S) -> String {
   hasher.result_str()
 }
 
+/// A pluggable hash function used to place vnodes and to resolve lookup keys on the ring.
+///
+/// Implement this to swap the default Sha1-backed hash for a faster non-cryptographic one
+/// (FNV, xxHash) or for a hash that's reproducible across languages.
+pub trait Hasher {
+  fn hash(&self, input: &str) -> u64;
+}
+
+/// The default `Hasher`. Backed by `hash_key`, truncated to the first 64 bits.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sha1Hasher;
 
+impl Hasher for Sha1Hasher {
+  fn hash(&self, input: &str) -> u64 {
+    let hex = hash_key(input);
+    u64::from_str_radix(&hex[0..16], 16).expect("sha1 hex digest is always valid hex")
+  }
+}
 
 pub trait Node: Clone + Debug + Display + Eq + Ord {
   fn name(&self) -> String;
+
+  /// The failure domain (rack, datacenter, ...) this node lives in, if any.
+  ///
+  /// Used by `Ring::get_replicas_zoned` to spread a key's replicas across distinct
+  /// zones. Nodes that don't care about zone diversity can leave this as `None`.
+  fn zone(&self) -> Option<String> {
+    None
+  }
 }
 
 #[derive(Debug, Eq)]
 pub struct VNode <'a, N> where N: Node + 'a {
   pub replica: usize,
   pub node: &'a N,
-  pub hash: String,
+  pub hash: u64,
 }
 
 impl<'a, N: Node + 'a> VNode<'a, N> {
-  pub fn new(replica: usize, node: &'a N) -> Self {
-    let hash = hash_key(format!("{}_{}", node.name(), replica).as_str());
+  pub fn new(replica: usize, node: &'a N, hasher: &Hasher) -> Self {
+    let hash = hasher.hash(format!("{}_{}", node.name(), replica).as_str());
 
     VNode {
       replica: replica,
@@ -76,8 +103,8 @@ impl<'a, N: Node + 'a> Display for VNode<'a, N> {
 
 pub type VNodes<'a, N> = Vec<VNode<'a, N>>;
 
-fn create_replicas_for_node<N: Node>(replicas: usize, node: &N) -> VNodes<N> {
-  let mut vnodes: VNodes<_> = (0..replicas).map(|r| VNode::new(r, node))
+fn create_replicas_for_node<N: Node>(replicas: usize, node: &N, hasher: &Hasher) -> VNodes<N> {
+  let mut vnodes: VNodes<_> = (0..replicas).map(|r| VNode::new(r, node, hasher))
                                            .collect();
   vnodes.sort();
   vnodes
@@ -87,15 +114,28 @@ pub struct Ring <'a, N> where N: Node + 'a {
   pub replicas: usize,
   pub nodes: Vec<Box<N>>,
   pub vnodes: VNodes<'a, N>,
+  pub hasher: Box<Hasher>,
+  pub weights: HashMap<String, usize>,
 }
 
 impl<'a, N: Node + 'a> Ring<'a, N> {
   pub fn new(replicas: usize, seed_node: &'a N) -> Self {
+    Ring::with_hasher(replicas, seed_node, Box::new(Sha1Hasher))
+  }
+
+  /// Build a ring with a custom `Hasher` instead of the default Sha1-backed one.
+  pub fn with_hasher(replicas: usize, seed_node: &'a N, hasher: Box<Hasher>) -> Self {
     // TODO VNodes should be created with references to &*ring.nodes[0] instead of seed_node
+    let vnodes = create_replicas_for_node(replicas, seed_node, &*hasher);
+    let mut weights = HashMap::new();
+    weights.insert(seed_node.name(), 1);
+
     Ring {
       replicas: replicas,
       nodes: vec![Box::new(seed_node.clone())],
-      vnodes: create_replicas_for_node(replicas, seed_node),
+      vnodes: vnodes,
+      hasher: hasher,
+      weights: weights,
     }
   }
 
@@ -108,62 +148,189 @@ impl<'a, N: Node + 'a> Ring<'a, N> {
     self.contains_name(search_node.name())
   }
 
+  /// The weight `node` was added with, or `1` if it isn't on the ring (the default
+  /// weight used by `add`).
+  pub fn weight_of(&self, node: &'a N) -> usize {
+    *self.weights.get(&node.name()).unwrap_or(&1)
+  }
+
   pub fn add(&mut self, node: &'a N) {
+    self.add_weighted(node, 1);
+  }
+
+  /// Add `node` with `weight` times the ring's usual number of vnodes, giving it
+  /// proportionally more key ownership. Use this to bias load toward bigger machines
+  /// in a heterogeneous cluster.
+  pub fn add_weighted(&mut self, node: &'a N, weight: usize) {
     if self.contains(node) {
       // The Ring already has this node
       return;
     }
 
     self.nodes.push(Box::new(node.clone()));
+    self.weights.insert(node.name(), weight);
 
-    let mut i = 0;
-    let mut new_vnodes = create_replicas_for_node(self.replicas, node);
-
-    // Insert our new vnodes in place.
-    while i < self.vnodes.len() {
-      if new_vnodes.is_empty() {
-        break;
-      }
+    let new_vnodes = create_replicas_for_node(self.replicas * weight, node, &*self.hasher);
 
-      if self.vnodes[i] >= new_vnodes[0] {
-        self.vnodes.insert(i, new_vnodes.remove(0));
-      }
-
-      i += 1;
-    }
-
-    // If we still have nodes left then they must have smaller hashes than the
-    // nodes in self.vnodes. Lets put them before the other vnodes.
-    while !new_vnodes.is_empty() {
-      self.vnodes.push(new_vnodes.remove(0));
+    // vnodes is kept sorted, and new_vnodes is sorted too, so each insert position
+    // can be found with a binary search rather than a linear scan.
+    for vnode in new_vnodes {
+      let pos = match self.vnodes.binary_search_by(|v| v.hash.cmp(&vnode.hash)) {
+        Ok(pos) | Err(pos) => pos,
+      };
+      self.vnodes.insert(pos, vnode);
     }
   }
 
   pub fn remove(&mut self, node: &'a N) {
-    if let Ok(i) = self.nodes.binary_search_by(|box_node| (**box_node).cmp(node)) {
+    // self.nodes is in insertion order, not sorted, so it can't be binary searched.
+    if let Some(i) = self.nodes.iter().position(|box_node| (**box_node).eq(node)) {
       self.nodes.remove(i);
-      self.vnodes.retain(|ref vnode| vnode.node.eq(node));
+      self.weights.remove(&node.name());
+      self.vnodes.retain(|ref vnode| !vnode.node.eq(node));
     }
   }
 
-  pub fn get_with_hash<S: Into<String>>(&self, hash: S) -> Option<&N>  {
+  // Find the index of the first vnode with hash >= key_hash, wrapping to the first
+  // vnode if key_hash is past the end of the ring.
+  fn circular_index(&self, key_hash: u64) -> usize {
+    match self.vnodes.binary_search_by(|v| v.hash.cmp(&key_hash)) {
+      Ok(pos) => pos,
+      Err(pos) => if pos == self.vnodes.len() { 0 } else { pos },
+    }
+  }
+
+  pub fn get_with_hash(&self, hash: u64) -> Option<&N>  {
     if self.vnodes.is_empty() {
       return None;
     }
 
-    let key_hash = hash.into();
-
-    // Find the first vnode with a hash >= key_hash. If we don't find
-    // one return the first vnode instead.
-    //
-    self.vnodes.iter()
-               .find(|&vnode| vnode.hash >= key_hash)
-               .map(|ref vnode| vnode.node)
-               .or_else(|| Some(self.vnodes[0].node))
+    Some(self.vnodes[self.circular_index(hash)].node)
   }
 
   pub fn get<'b, S: Into<&'b str>>(&self, key: S) -> Option<&N>  {
-    self.get_with_hash(hash_key(key))
+    self.get_with_hash(self.hasher.hash(key.into()))
+  }
+
+  /// Return up to `n` distinct physical nodes that own `key`, for quorum reads/writes.
+  ///
+  /// Walks the ring circularly starting at the first vnode whose hash is `>= hash(key)`,
+  /// collecting each vnode's node and skipping nodes (compared by `name()`) already in the
+  /// result, until `n` distinct nodes are found or every node has been seen.
+  pub fn get_replicas<'b, S: Into<&'b str>>(&self, key: S, n: usize) -> Vec<&N> {
+    self.get_replicas_with_hash(self.hasher.hash(key.into()), n)
+  }
+
+  fn get_replicas_with_hash(&self, hash: u64, n: usize) -> Vec<&N> {
+    let mut result: Vec<&N> = Vec::new();
+
+    if self.vnodes.is_empty() || n == 0 {
+      return result;
+    }
+
+    let start = self.circular_index(hash);
+
+    for i in 0..self.vnodes.len() {
+      let vnode = &self.vnodes[(start + i) % self.vnodes.len()];
+
+      if result.iter().any(|existing: &&N| existing.name() == vnode.node.name()) {
+        continue;
+      }
+
+      result.push(vnode.node);
+
+      if result.len() == n {
+        break;
+      }
+    }
+
+    result
+  }
+
+  /// Like `get_replicas`, but spreads the result across distinct `Node::zone()`s where
+  /// possible, so replicas of a key don't all land in the same rack/datacenter.
+  ///
+  /// Falls back to placing replicas in the same zone once fewer than `n` distinct zones
+  /// are available across the ring's nodes.
+  pub fn get_replicas_zoned<'b, S: Into<&'b str>>(&self, key: S, n: usize) -> Vec<&N> {
+    self.get_replicas_zoned_with_hash(self.hasher.hash(key.into()), n)
+  }
+
+  fn get_replicas_zoned_with_hash(&self, hash: u64, n: usize) -> Vec<&N> {
+    let mut result: Vec<&N> = Vec::new();
+
+    if self.vnodes.is_empty() || n == 0 {
+      return result;
+    }
+
+    let start = self.circular_index(hash);
+    let mut zones: HashSet<Option<String>> = HashSet::new();
+
+    // First pass: maximize zone diversity, skipping any node whose zone we already picked.
+    for i in 0..self.vnodes.len() {
+      let vnode = &self.vnodes[(start + i) % self.vnodes.len()];
+
+      if result.iter().any(|existing: &&N| existing.name() == vnode.node.name()) {
+        continue;
+      }
+
+      if zones.contains(&vnode.node.zone()) {
+        continue;
+      }
+
+      zones.insert(vnode.node.zone());
+      result.push(vnode.node);
+
+      if result.len() == n {
+        return result;
+      }
+    }
+
+    // Fewer than n distinct zones exist on the ring - fall back to filling the rest
+    // from any zone, same-zone nodes included.
+    for i in 0..self.vnodes.len() {
+      let vnode = &self.vnodes[(start + i) % self.vnodes.len()];
+
+      if result.iter().any(|existing: &&N| existing.name() == vnode.node.name()) {
+        continue;
+      }
+
+      result.push(vnode.node);
+
+      if result.len() == n {
+        break;
+      }
+    }
+
+    result
+  }
+
+  /// Precompute a fixed table of `2^bits` partitions, each mapped to up to `replication`
+  /// owning nodes (fewer if the ring has fewer distinct nodes), instead of hashing every
+  /// key at lookup time.
+  ///
+  /// A key then resolves with an O(1) array index: the partition is the top `bits` bits of
+  /// `self.hasher.hash(key)`, and its owners are `table[partition]`. The
+  /// table can be diffed across membership changes to find exactly which partitions moved,
+  /// which is what makes rebalancing cheap.
+  ///
+  /// Trade-off: with few `bits` and a small cluster, partitions are coarser than per-key
+  /// hashing so fairness suffers; the table's memory grows as `2^bits`, so `bits` shouldn't
+  /// be pushed higher than the cluster needs to balance well.
+  ///
+  /// Panics if `bits >= 64`; there's no hash space left to partition at that point.
+  pub fn build_partition_table(&self, bits: usize, replication: usize) -> Vec<Vec<&N>> {
+    assert!(bits < 64, "build_partition_table: bits must be < 64, got {}", bits);
+
+    let partition_count = 1usize << bits;
+    let shift = 64 - bits;
+
+    (0..partition_count).map(|partition| {
+      // bits == 0 means a single partition covering the whole hash space; shifting by
+      // 64 would overflow, so just use hash 0 as that partition's representative.
+      let representative = if bits == 0 { 0 } else { (partition as u64) << shift };
+      self.get_replicas_with_hash(representative, replication)
+    }).collect()
   }
 }
 
@@ -273,4 +440,202 @@ mod tests {
     assert_eq!(ring.vnodes.len(), 6);
   }
 
+  #[test]
+  fn get_replicas_test() {
+    let test_node1 = TestNode::new("Foo");
+    let test_node2 = TestNode::new("Bar");
+    let test_node3 = TestNode::new("Baz");
+    let mut ring = Ring::new(3, &test_node1);
+
+    ring.add(&test_node2);
+    ring.add(&test_node3);
+
+    // n distinct nodes are returned, with no repeats.
+    let replicas = ring.get_replicas("some key", 3);
+    assert_eq!(replicas.len(), 3);
+    assert!(replicas.contains(&&test_node1));
+    assert!(replicas.contains(&&test_node2));
+    assert!(replicas.contains(&&test_node3));
+
+    // Asking for more nodes than exist stops once every distinct node is found,
+    // exercising the wraparound walk.
+    let replicas = ring.get_replicas("some key", 10);
+    assert_eq!(replicas.len(), 3);
+  }
+
+  struct ReverseHasher;
+
+  impl Hasher for ReverseHasher {
+    fn hash(&self, input: &str) -> u64 {
+      // Deliberately different from Sha1Hasher's placement, so swapping it changes
+      // which vnode a given input lands on.
+      !Sha1Hasher.hash(input)
+    }
+  }
+
+  #[test]
+  fn with_hasher_test() {
+    let test_node1 = TestNode::new("Foo");
+    let test_node2 = TestNode::new("Bar");
+
+    let mut sha1_ring = Ring::new(3, &test_node1);
+    let mut custom_ring = Ring::with_hasher(3, &test_node1, Box::new(ReverseHasher));
+
+    sha1_ring.add(&test_node2);
+    custom_ring.add(&test_node2);
+
+    // Same nodes, different hasher: the vnode hashes placed on the ring differ.
+    let sha1_hashes: Vec<u64> = sha1_ring.vnodes.iter().map(|vnode| vnode.hash).collect();
+    let custom_hashes: Vec<u64> = custom_ring.vnodes.iter().map(|vnode| vnode.hash).collect();
+    assert!(sha1_hashes != custom_hashes);
+  }
+
+  #[test]
+  fn vnodes_stay_sorted_after_binary_search_insert_test() {
+    let test_node1 = TestNode::new("Foo");
+    let test_node2 = TestNode::new("Bar");
+    let test_node3 = TestNode::new("Baz");
+    let mut ring = Ring::new(5, &test_node1);
+
+    ring.add(&test_node2);
+    ring.add(&test_node3);
+
+    // add() inserts each new vnode at its binary-searched position, so the ring
+    // should remain sorted by numeric hash with no linear-scan fallback needed.
+    let hashes: Vec<u64> = ring.vnodes.iter().map(|vnode| vnode.hash).collect();
+    let mut sorted_hashes = hashes.clone();
+    sorted_hashes.sort();
+    assert_eq!(hashes, sorted_hashes);
+  }
+
+  #[test]
+  fn add_weighted_test() {
+    let test_node1 = TestNode::new("Foo");
+    let test_node2 = TestNode::new("Bar");
+    let mut ring = Ring::new(3, &test_node1);
+
+    ring.add_weighted(&test_node2, 4);
+
+    // The weighted node gets replicas * weight vnodes, and the weight is retrievable.
+    assert_eq!(ring.vnodes.len(), 3 + 3 * 4);
+    assert_eq!(ring.weight_of(&test_node2), 4);
+
+    ring.remove(&test_node2);
+
+    // remove() must delete every vnode the weighted node created, regardless of count.
+    assert_eq!(ring.vnodes.len(), 3);
+    assert!(ring.vnodes.iter().all(|vnode| vnode.node.name() != test_node2.name()));
+    assert_eq!(ring.weight_of(&test_node2), 1);
+  }
+
+  #[test]
+  fn remove_out_of_sort_order_test() {
+    // self.nodes is in insertion order, not sorted by name, so removing a node whose
+    // name would sort *before* an earlier-inserted node must still find and drop it.
+    let test_node1 = TestNode::new("Foo");
+    let test_node2 = TestNode::new("Bar");
+    let test_node3 = TestNode::new("Baz");
+    let mut ring = Ring::new(3, &test_node1);
+
+    ring.add(&test_node2);
+    ring.add(&test_node3);
+
+    // "Bar" was inserted after "Foo" but sorts before it alphabetically.
+    ring.remove(&test_node2);
+
+    assert!(!ring.contains(&test_node2));
+    assert_eq!(ring.vnodes.len(), 6);
+    assert!(ring.vnodes.iter().all(|vnode| vnode.node.name() != test_node2.name()));
+  }
+
+  #[derive(Debug, Clone, Eq, Ord)]
+  struct ZonedTestNode {
+    id: String,
+    zone: &'static str,
+  }
+
+  impl ZonedTestNode {
+    fn new(id: &str, zone: &'static str) -> ZonedTestNode {
+      ZonedTestNode { id: id.to_string(), zone: zone }
+    }
+  }
+
+  impl Node for ZonedTestNode {
+    fn name(&self) -> String {
+      self.id.clone()
+    }
+
+    fn zone(&self) -> Option<String> {
+      Some(self.zone.to_string())
+    }
+  }
+
+  impl PartialOrd for ZonedTestNode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+      Some(self.name().cmp(&other.name()))
+    }
+  }
+
+  impl PartialEq for ZonedTestNode {
+    fn eq(&self, other: &Self) -> bool {
+      self.name() == other.name()
+    }
+  }
+
+  impl fmt::Display for ZonedTestNode {
+      fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+          write!(f, "ZonedTestNode<({}:{})>", self.name(), self.zone)
+      }
+  }
+
+  #[test]
+  fn get_replicas_zoned_test() {
+    // Three nodes in zone "a", one in zone "b".
+    let node_a1 = ZonedTestNode::new("a1", "a");
+    let node_a2 = ZonedTestNode::new("a2", "a");
+    let node_a3 = ZonedTestNode::new("a3", "a");
+    let node_b1 = ZonedTestNode::new("b1", "b");
+
+    let mut ring = Ring::new(5, &node_a1);
+    ring.add(&node_a2);
+    ring.add(&node_a3);
+    ring.add(&node_b1);
+
+    // Asking for as many replicas as there are distinct zones must pick one node
+    // per zone, even when the nearest vnodes on the ring are all in zone "a".
+    let replicas = ring.get_replicas_zoned("some key", 2);
+    let zones: HashSet<Option<String>> = replicas.iter().map(|node| node.zone()).collect();
+    assert_eq!(replicas.len(), 2);
+    assert_eq!(zones.len(), 2);
+
+    // Asking for more replicas than there are zones falls back to same-zone nodes
+    // to fill the remainder.
+    let replicas = ring.get_replicas_zoned("some key", 4);
+    assert_eq!(replicas.len(), 4);
+  }
+
+  #[test]
+  fn build_partition_table_test() {
+    let test_node1 = TestNode::new("Foo");
+    let test_node2 = TestNode::new("Bar");
+    let test_node3 = TestNode::new("Baz");
+    let mut ring = Ring::new(3, &test_node1);
+
+    ring.add(&test_node2);
+    ring.add(&test_node3);
+
+    let bits = 4;
+    let table = ring.build_partition_table(bits, 2);
+    assert_eq!(table.len(), 1 << bits);
+
+    // Every partition's first owner must agree with a plain get_with_hash() lookup of
+    // that partition's representative hash (the lowest hash in the partition).
+    for (partition, owners) in table.iter().enumerate() {
+      assert_eq!(owners.len(), 2);
+
+      let representative = (partition as u64) << (64 - bits);
+      assert_eq!(ring.get_with_hash(representative), Some(owners[0]));
+    }
+  }
+
 }